@@ -0,0 +1,325 @@
+//! Measure round-trip latency of a midi connection
+use crate::{MidiIn, MidiOut};
+use core::fmt::Debug;
+use embedded_hal::serial;
+use midi_types::{Channel, MidiMessage, Note};
+
+/// Source of time for the `LatencyTester`, implement this on top of a timer peripheral. The
+/// value is allowed to wrap around.
+pub trait Clock {
+    /// Current time in microseconds
+    fn now_micros(&mut self) -> u32;
+}
+
+/// Errors returned when measuring latency
+#[derive(Debug, Clone, PartialEq)]
+pub enum LatencyError<E> {
+    /// No echo of the marker was received before the timeout expired
+    Timeout,
+    /// Error writing to the underlying serial connection, receive errors are counted in the
+    /// statistics instead
+    Serial(E),
+}
+
+/// Round-trip time statistics in microseconds
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LatencyStats {
+    /// Number of successful measurements
+    pub count: u32,
+    /// Number of markers that timed out
+    pub timeouts: u32,
+    /// Number of receive errors, like framing or overrun errors, while waiting for an echo
+    pub errors: u32,
+    /// Shortest round-trip time, none if nothing has been measured yet
+    pub min: Option<u32>,
+    /// Longest round-trip time, none if nothing has been measured yet
+    pub max: Option<u32>,
+    total: u64,
+}
+
+impl LatencyStats {
+    /// Average round-trip time, none if nothing has been measured yet
+    pub fn average(&self) -> Option<u32> {
+        match self.count {
+            0 => None,
+            count => Some((self.total / u64::from(count)) as u32),
+        }
+    }
+
+    /// Peak-to-peak jitter, the difference between the longest and shortest round-trip time. None
+    /// if nothing has been measured yet.
+    pub fn jitter(&self) -> Option<u32> {
+        Some(self.max? - self.min?)
+    }
+
+    fn add(&mut self, micros: u32) {
+        self.min = Some(self.min.map_or(micros, |min| min.min(micros)));
+        self.max = Some(self.max.map_or(micros, |max| max.max(micros)));
+        self.count += 1;
+        self.total += u64::from(micros);
+    }
+}
+
+/// Sends a note-on marker, waits for it to be echoed back and keeps statistics on the round-trip
+/// times. The velocity of the marker is used as a sequence number so late echoes of a marker that
+/// timed out are not mistaken for the current one. A note-off is sent after each marker so
+/// devices in the loop are not left with hanging notes.
+#[derive(Debug)]
+pub struct LatencyTester<C> {
+    clock: C,
+    channel: Channel,
+    note: Note,
+    timeout: u32,
+    sequence: u8,
+    stats: LatencyStats,
+}
+
+impl<C> LatencyTester<C>
+where
+    C: Clock,
+{
+    /// Create a tester sending markers on `channel` and `note`, giving up on an echo after
+    /// `timeout` microseconds.
+    pub fn new(clock: C, channel: Channel, note: Note, timeout: u32) -> Self {
+        LatencyTester {
+            clock,
+            channel,
+            note,
+            timeout,
+            sequence: 0,
+            stats: LatencyStats::default(),
+        }
+    }
+
+    /// Statistics of all measurements so far
+    pub fn stats(&self) -> LatencyStats {
+        self.stats
+    }
+
+    /// Clear the statistics
+    pub fn reset(&mut self) {
+        self.stats = LatencyStats::default();
+    }
+
+    /// Release the clock
+    pub fn release(self) -> C {
+        self.clock
+    }
+
+    /// Send a marker and block until its echo is received or the timeout expires. Returns the
+    /// round-trip time in microseconds, this includes the time it takes to transmit the marker.
+    /// Receive errors do not end the measurement, they are counted in the statistics and the
+    /// tester keeps waiting for the echo.
+    pub fn measure<TX, RX, E>(
+        &mut self,
+        midi_out: &mut MidiOut<TX>,
+        midi_in: &mut MidiIn<RX>,
+    ) -> Result<u32, LatencyError<E>>
+    where
+        TX: serial::Write<u8, Error = E>,
+        RX: serial::Read<u8, Error = E>,
+        E: Debug,
+    {
+        // Sequence numbers run from 1 to 127, a velocity of 0 would be a note-off
+        self.sequence = self.sequence % 127 + 1;
+        let marker = MidiMessage::NoteOn(self.channel, self.note, self.sequence.into());
+
+        let start = self.clock.now_micros();
+        midi_out.write(&marker).map_err(LatencyError::Serial)?;
+
+        let result = loop {
+            match midi_in.read() {
+                Ok(message) if message == marker => {
+                    break Ok(self.clock.now_micros().wrapping_sub(start));
+                }
+                Ok(_) | Err(nb::Error::WouldBlock) => (),
+                Err(nb::Error::Other(_)) => self.stats.errors += 1,
+            }
+
+            if self.clock.now_micros().wrapping_sub(start) > self.timeout {
+                break Err(LatencyError::Timeout);
+            }
+        };
+
+        // Update the statistics first so a failing note-off does not lose the measurement
+        match result {
+            Ok(micros) => self.stats.add(micros),
+            Err(_) => self.stats.timeouts += 1,
+        }
+
+        midi_out
+            .write(&MidiMessage::NoteOff(self.channel, self.note, 0.into()))
+            .map_err(LatencyError::Serial)?;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use embedded_hal_mock::serial::{Mock, Transaction};
+    use embedded_hal_mock::MockError;
+    use std::io::ErrorKind;
+    use std::vec::Vec;
+
+    /// Clock that advances a fixed number of microseconds every time it is read
+    struct StepClock {
+        now: u32,
+        step: u32,
+    }
+
+    impl Clock for StepClock {
+        fn now_micros(&mut self) -> u32 {
+            let now = self.now;
+            self.now = self.now.wrapping_add(self.step);
+            now
+        }
+    }
+
+    fn tester(start: u32, step: u32, timeout: u32) -> LatencyTester<StepClock> {
+        LatencyTester::new(StepClock { now: start, step }, 15.into(), 0.into(), timeout)
+    }
+
+    fn writes(bytes: &[u8]) -> Vec<Transaction<u8>> {
+        bytes.iter().map(|byte| Transaction::write(*byte)).collect()
+    }
+
+    fn reads(bytes: &[u8]) -> Vec<Transaction<u8>> {
+        bytes.iter().map(|byte| Transaction::read(*byte)).collect()
+    }
+
+    #[test]
+    fn should_measure_round_trip() {
+        let mut tx = Mock::new(&writes(&[0x9F, 0x00, 0x01, 0x8F, 0x00, 0x00]));
+        let mut rx = Mock::new(&reads(&[0x9F, 0x00, 0x01]));
+        let mut midi_out = MidiOut::new(tx.clone());
+        let mut midi_in = MidiIn::new(rx.clone());
+        let mut tester = tester(1000, 100, 10_000);
+
+        // The clock is read at the start, after each incomplete read and when the echo completes
+        assert_eq!(tester.measure(&mut midi_out, &mut midi_in), Ok(300));
+
+        tx.done();
+        rx.done();
+    }
+
+    #[test]
+    fn should_ignore_other_messages() {
+        let mut tx = Mock::new(&writes(&[0x9F, 0x00, 0x01, 0x8F, 0x00, 0x00]));
+        let mut rx = Mock::new(&reads(&[0xF8, 0x9F, 0x00, 0x02, 0x9F, 0x00, 0x01]));
+        let mut midi_out = MidiOut::new(tx.clone());
+        let mut midi_in = MidiIn::new(rx.clone());
+        let mut tester = tester(0, 10, 10_000);
+
+        assert_eq!(tester.measure(&mut midi_out, &mut midi_in), Ok(70));
+
+        tx.done();
+        rx.done();
+    }
+
+    #[test]
+    fn should_time_out_without_echo() {
+        let mut tx = Mock::new(&writes(&[0x9F, 0x00, 0x01, 0x8F, 0x00, 0x00]));
+        let mut rx = Mock::new(&[
+            Transaction::read_error(nb::Error::WouldBlock),
+            Transaction::read_error(nb::Error::WouldBlock),
+        ]);
+        let mut midi_out = MidiOut::new(tx.clone());
+        let mut midi_in = MidiIn::new(rx.clone());
+        let mut tester = tester(u32::MAX - 50, 100, 150);
+
+        assert_eq!(
+            tester.measure(&mut midi_out, &mut midi_in),
+            Err(LatencyError::Timeout)
+        );
+        assert_eq!(tester.stats().timeouts, 1);
+        assert_eq!(tester.stats().count, 0);
+
+        tx.done();
+        rx.done();
+    }
+
+    #[test]
+    fn should_count_receive_errors_and_keep_waiting() {
+        let error = MockError::Io(ErrorKind::Other);
+        let mut tx = Mock::new(&writes(&[0x9F, 0x00, 0x01, 0x8F, 0x00, 0x00]));
+        let mut rx = Mock::new(&[
+            Transaction::read_error(nb::Error::Other(error)),
+            Transaction::read(0x9F),
+            Transaction::read(0x00),
+            Transaction::read(0x01),
+        ]);
+        let mut midi_out = MidiOut::new(tx.clone());
+        let mut midi_in = MidiIn::new(rx.clone());
+        let mut tester = tester(0, 10, 10_000);
+
+        assert_eq!(tester.measure(&mut midi_out, &mut midi_in), Ok(40));
+        assert_eq!(tester.stats().errors, 1);
+        assert_eq!(tester.stats().count, 1);
+
+        tx.done();
+        rx.done();
+    }
+
+    #[test]
+    fn should_keep_measurement_when_note_off_fails() {
+        let error = MockError::Io(ErrorKind::Other);
+        let mut tx = Mock::new(&[
+            Transaction::write(0x9F),
+            Transaction::write(0x00),
+            Transaction::write(0x01),
+            Transaction::write_error(0x8F, nb::Error::Other(error.clone())),
+        ]);
+        let mut rx = Mock::new(&reads(&[0x9F, 0x00, 0x01]));
+        let mut midi_out = MidiOut::new(tx.clone());
+        let mut midi_in = MidiIn::new(rx.clone());
+        let mut tester = tester(0, 10, 10_000);
+
+        assert_eq!(
+            tester.measure(&mut midi_out, &mut midi_in),
+            Err(LatencyError::Serial(error))
+        );
+        assert_eq!(tester.stats().count, 1);
+        assert_eq!(tester.stats().min, Some(30));
+
+        tx.done();
+        rx.done();
+    }
+
+    #[test]
+    fn should_use_velocity_as_sequence_number() {
+        let mut tx = Mock::new(&writes(&[
+            0x9F, 0x00, 0x01, 0x8F, 0x00, 0x00, // First marker
+            0x9F, 0x00, 0x02, 0x8F, 0x00, 0x00, // Second marker
+        ]));
+        let mut rx = Mock::new(&reads(&[0x9F, 0x00, 0x01, 0x00, 0x02]));
+        let mut midi_out = MidiOut::new(tx.clone());
+        let mut midi_in = MidiIn::new(rx.clone());
+        let mut tester = tester(0, 10, 10_000);
+
+        assert_eq!(tester.measure(&mut midi_out, &mut midi_in), Ok(30));
+        assert_eq!(tester.measure(&mut midi_out, &mut midi_in), Ok(20));
+
+        tx.done();
+        rx.done();
+    }
+
+    #[test]
+    fn should_keep_statistics() {
+        let mut stats = LatencyStats::default();
+        assert_eq!(stats.average(), None);
+        assert_eq!(stats.jitter(), None);
+        assert_eq!(stats.min, None);
+
+        stats.add(300);
+        stats.add(100);
+        stats.add(200);
+
+        assert_eq!(stats.count, 3);
+        assert_eq!(stats.min, Some(100));
+        assert_eq!(stats.max, Some(300));
+        assert_eq!(stats.average(), Some(200));
+        assert_eq!(stats.jitter(), Some(200));
+    }
+}
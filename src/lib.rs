@@ -1,14 +1,19 @@
 //! *Midi driver on top of embedded hal serial communications*
 //!
 #![no_std]
+mod latency;
 #[warn(missing_debug_implementations, missing_docs)]
 mod parser;
+mod probability;
+pub mod smf;
 
 use core::fmt::Debug;
 use embedded_hal::serial;
+pub use latency::{Clock, LatencyError, LatencyStats, LatencyTester};
 pub use midi_types::{Channel, Control, MidiMessage, Note, Program};
 use nb::block;
 pub use parser::MidiParser;
+pub use probability::{GateMode, ProbabilityGate, RandomSource, XorShift32};
 
 pub struct MidiIn<RX> {
     rx: RX,
@@ -59,66 +64,66 @@ where
     }
 
     pub fn write(&mut self, message: &MidiMessage) -> Result<(), E> {
-        match message {
-            &MidiMessage::NoteOn(channel, note, velocity) => {
+        match *message {
+            MidiMessage::NoteOn(channel, note, velocity) => {
                 self.write_channel_message(0x90, channel.into(), &[note.into(), velocity.into()])?;
             }
-            &MidiMessage::NoteOff(channel, note, velocity) => {
+            MidiMessage::NoteOff(channel, note, velocity) => {
                 self.write_channel_message(0x80, channel.into(), &[note.into(), velocity.into()])?;
             }
-            &MidiMessage::KeyPressure(channel, note, value) => {
+            MidiMessage::KeyPressure(channel, note, value) => {
                 self.write_channel_message(0xA0, channel.into(), &[note.into(), value.into()])?;
             }
-            &MidiMessage::ControlChange(channel, control, value) => {
+            MidiMessage::ControlChange(channel, control, value) => {
                 self.write_channel_message(0xB0, channel.into(), &[control.into(), value.into()])?;
             }
-            &MidiMessage::ProgramChange(channel, program) => {
+            MidiMessage::ProgramChange(channel, program) => {
                 self.write_channel_message(0xC0, channel.into(), &[program.into()])?;
             }
-            &MidiMessage::ChannelPressure(channel, value) => {
+            MidiMessage::ChannelPressure(channel, value) => {
                 self.write_channel_message(0xD0, channel.into(), &[value.into()])?;
             }
-            &MidiMessage::PitchBendChange(channel, value) => {
+            MidiMessage::PitchBendChange(channel, value) => {
                 let (value_lsb, value_msb) = value.into();
                 self.write_channel_message(0xE0, channel.into(), &[value_lsb, value_msb])?;
             }
-            &MidiMessage::QuarterFrame(value) => {
+            MidiMessage::QuarterFrame(value) => {
                 block!(self.tx.write(0xF1))?;
                 block!(self.tx.write(value.into()))?;
                 self.last_status = None;
             }
-            &MidiMessage::SongPositionPointer(value) => {
+            MidiMessage::SongPositionPointer(value) => {
                 let (value_lsb, value_msb) = value.into();
                 block!(self.tx.write(0xF2))?;
                 block!(self.tx.write(value_lsb))?;
                 block!(self.tx.write(value_msb))?;
                 self.last_status = None;
             }
-            &MidiMessage::SongSelect(value) => {
+            MidiMessage::SongSelect(value) => {
                 block!(self.tx.write(0xF3))?;
                 block!(self.tx.write(value.into()))?;
                 self.last_status = None;
             }
-            &MidiMessage::TuneRequest => {
+            MidiMessage::TuneRequest => {
                 block!(self.tx.write(0xF6))?;
                 self.last_status = None;
             }
-            &MidiMessage::TimingClock => {
+            MidiMessage::TimingClock => {
                 block!(self.tx.write(0xF8))?;
             }
-            &MidiMessage::Start => {
+            MidiMessage::Start => {
                 block!(self.tx.write(0xFA))?;
             }
-            &MidiMessage::Continue => {
+            MidiMessage::Continue => {
                 block!(self.tx.write(0xFB))?;
             }
-            &MidiMessage::Stop => {
+            MidiMessage::Stop => {
                 block!(self.tx.write(0xFC))?;
             }
-            &MidiMessage::ActiveSensing => {
+            MidiMessage::ActiveSensing => {
                 block!(self.tx.write(0xFE))?;
             }
-            &MidiMessage::Reset => {
+            MidiMessage::Reset => {
                 block!(self.tx.write(0xFF))?;
             }
         }
@@ -151,13 +156,13 @@ mod tests {
 
     fn verify_writes(messages: &[MidiMessage], bytes: &[u8]) {
         let expectations: Vec<serial::Transaction<u8>> = bytes
-            .into_iter()
+            .iter()
             .map(|byte| serial::Transaction::write(*byte))
             .collect();
         let serial = serial::Mock::new(&expectations);
         let mut midi_out = MidiOut::new(serial);
         for message in messages {
-            midi_out.write(&message).unwrap();
+            midi_out.write(message).unwrap();
         }
         let mut serial = midi_out.release();
         serial.done();
@@ -52,6 +52,12 @@ fn split_message_and_channel(byte: u8) -> (u8, Channel) {
     (byte & 0xf0u8, (byte & 0x0fu8).into())
 }
 
+impl Default for MidiParser {
+    fn default() -> Self {
+        MidiParser::new()
+    }
+}
+
 /// State machine for parsing Midi data, can be fed bytes one-by-one, and returns parsed Midi
 /// messages whenever one is completed.
 impl MidiParser {
@@ -657,7 +663,7 @@ mod tests {
         /// Test helper function, asserts if a slice of bytes parses to some set of midi events
         fn assert_result(&mut self, bytes: &[u8], expected_events: &[MidiMessage]) {
             let events: Vec<MidiMessage> = bytes
-                .into_iter()
+                .iter()
                 .filter_map(|byte| self.parse_byte(*byte))
                 .collect();
 
@@ -0,0 +1,359 @@
+//! Randomly gate notes based on a probability
+use midi_types::{Channel, MidiMessage, Note};
+
+/// Number of notes that can be retriggered while they are still held
+const RETRIGGER_SLOTS: usize = 8;
+
+/// Source of random numbers for the `ProbabilityGate`. Implement this to use a hardware random
+/// number generator instead of the built-in `XorShift32`.
+pub trait RandomSource {
+    /// Return the next random 32 bit value
+    fn next_u32(&mut self) -> u32;
+}
+
+/// Small seedable pseudo random number generator, fast enough for use on microcontrollers but not
+/// suitable for anything that needs real randomness.
+#[derive(Debug, Clone, PartialEq)]
+pub struct XorShift32 {
+    state: u32,
+}
+
+impl XorShift32 {
+    /// Create a new generator from a seed. Xorshift breaks on a seed of zero so that is replaced
+    /// by a fixed non-zero value.
+    pub fn new(seed: u32) -> Self {
+        XorShift32 {
+            state: if seed == 0 { 0x2545_f491 } else { seed },
+        }
+    }
+}
+
+impl RandomSource for XorShift32 {
+    fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+}
+
+/// Determines how often the gate rolls the dice
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GateMode {
+    /// Every note-on message gets its own roll
+    PerNote,
+    /// All note-on messages in the same step share a single roll, so chords are passed or dropped
+    /// as a whole. Call `next_step` to advance to the next step.
+    PerStep,
+}
+
+/// Passes note-on messages with a configurable probability. Note-off messages are only passed
+/// when the matching note-on was passed so no notes are left hanging. Retriggered notes are
+/// counted, a note that was passed twice passes two note-offs. Up to eight notes can be
+/// retriggered at the same time, when all slots are in use a retrigger of a note that is already
+/// sounding is dropped. All other messages are passed unchanged.
+///
+/// Note tracking takes a 256 byte bitset and 24 bytes for retriggers, plus the random source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProbabilityGate<R = XorShift32> {
+    rng: R,
+    probability: u8,
+    mode: GateMode,
+    step_roll: Option<bool>,
+    active: [u128; 16],
+    retriggers: [Retrigger; RETRIGGER_SLOTS],
+}
+
+/// Note-ons passed for a note on top of the one recorded in the active bitset
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+struct Retrigger {
+    channel: u8,
+    note: u8,
+    count: u8,
+}
+
+impl ProbabilityGate<XorShift32> {
+    /// Create a gate using the built-in pseudo random number generator seeded with `seed`
+    pub fn with_seed(seed: u32, probability: u8, mode: GateMode) -> Self {
+        ProbabilityGate::new(XorShift32::new(seed), probability, mode)
+    }
+}
+
+impl<R> ProbabilityGate<R>
+where
+    R: RandomSource,
+{
+    /// Create a gate that passes note-on messages with `probability` percent chance, values over
+    /// 100 are treated as 100.
+    pub fn new(rng: R, probability: u8, mode: GateMode) -> Self {
+        ProbabilityGate {
+            rng,
+            probability: probability.min(100),
+            mode,
+            step_roll: None,
+            active: [0; 16],
+            retriggers: [Retrigger::default(); RETRIGGER_SLOTS],
+        }
+    }
+
+    /// Change the probability in percent, values over 100 are treated as 100
+    pub fn set_probability(&mut self, probability: u8) {
+        self.probability = probability.min(100);
+    }
+
+    /// Current probability in percent
+    pub fn probability(&self) -> u8 {
+        self.probability
+    }
+
+    /// Advance to the next step, the next note-on will be rolled for again. Only has an effect in
+    /// `GateMode::PerStep`.
+    pub fn next_step(&mut self) {
+        self.step_roll = None;
+    }
+
+    /// Release the random number generator
+    pub fn release(self) -> R {
+        self.rng
+    }
+
+    /// Process a message, returns the message if it passes the gate or none if it was dropped
+    pub fn process(&mut self, message: MidiMessage) -> Option<MidiMessage> {
+        match message {
+            MidiMessage::NoteOn(channel, note, velocity) if u8::from(velocity) > 0 => {
+                if self.roll() && self.set_active(channel, note) {
+                    Some(message)
+                } else {
+                    None
+                }
+            }
+            // A note-on with velocity zero is a note-off
+            MidiMessage::NoteOn(channel, note, _) | MidiMessage::NoteOff(channel, note, _) => {
+                if self.clear_active(channel, note) {
+                    Some(message)
+                } else {
+                    None
+                }
+            }
+            _ => Some(message),
+        }
+    }
+
+    fn roll(&mut self) -> bool {
+        match self.mode {
+            GateMode::PerNote => self.roll_dice(),
+            GateMode::PerStep => match self.step_roll {
+                Some(roll) => roll,
+                None => {
+                    let roll = self.roll_dice();
+                    self.step_roll = Some(roll);
+                    roll
+                }
+            },
+        }
+    }
+
+    fn roll_dice(&mut self) -> bool {
+        // Scale the random value to 0..100 instead of using modulo to avoid bias
+        let value = (u64::from(self.rng.next_u32()) * 100) >> 32;
+        value < u64::from(self.probability)
+    }
+
+    /// Record a passed note-on, returns false if a retrigger could not be recorded
+    fn set_active(&mut self, channel: Channel, note: Note) -> bool {
+        let notes = &mut self.active[channel_index(channel)];
+        if *notes & note_bit(note) == 0 {
+            *notes |= note_bit(note);
+            return true;
+        }
+
+        let (channel, note) = (u8::from(channel), u8::from(note));
+        if let Some(retrigger) = self.retrigger(channel, note) {
+            if retrigger.count == u8::MAX {
+                return false;
+            }
+            retrigger.count += 1;
+            return true;
+        }
+
+        match self.retriggers.iter_mut().find(|slot| slot.count == 0) {
+            Some(slot) => {
+                *slot = Retrigger {
+                    channel,
+                    note,
+                    count: 1,
+                };
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove a passed note-on for a note, returns whether the note was active
+    fn clear_active(&mut self, channel: Channel, note: Note) -> bool {
+        let notes = &mut self.active[channel_index(channel)];
+        if *notes & note_bit(note) == 0 {
+            return false;
+        }
+
+        match self.retrigger(u8::from(channel), u8::from(note)) {
+            Some(retrigger) => retrigger.count -= 1,
+            None => self.active[channel_index(channel)] &= !note_bit(note),
+        }
+        true
+    }
+
+    fn retrigger(&mut self, channel: u8, note: u8) -> Option<&mut Retrigger> {
+        self.retriggers
+            .iter_mut()
+            .find(|slot| slot.count > 0 && slot.channel == channel && slot.note == note)
+    }
+}
+
+fn channel_index(channel: Channel) -> usize {
+    (u8::from(channel) & 0x0f) as usize
+}
+
+fn note_bit(note: Note) -> u128 {
+    1u128 << (u8::from(note) & 0x7f)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use std::{vec, vec::Vec};
+
+    /// Random source that returns a fixed sequence of values
+    struct FixedRandom(Vec<u32>);
+
+    impl RandomSource for FixedRandom {
+        fn next_u32(&mut self) -> u32 {
+            self.0.remove(0)
+        }
+    }
+
+    const LOW: u32 = 0;
+    const HIGH: u32 = u32::MAX;
+
+    fn note_on(note: u8) -> MidiMessage {
+        MidiMessage::NoteOn(1.into(), note.into(), 0x40.into())
+    }
+
+    fn note_off(note: u8) -> MidiMessage {
+        MidiMessage::NoteOff(1.into(), note.into(), 0x40.into())
+    }
+
+    #[test]
+    fn should_pass_note_on_when_roll_is_below_probability() {
+        let mut gate = ProbabilityGate::new(FixedRandom(vec![LOW]), 50, GateMode::PerNote);
+        assert_eq!(gate.process(note_on(60)), Some(note_on(60)));
+    }
+
+    #[test]
+    fn should_drop_note_on_when_roll_is_above_probability() {
+        let mut gate = ProbabilityGate::new(FixedRandom(vec![HIGH]), 50, GateMode::PerNote);
+        assert_eq!(gate.process(note_on(60)), None);
+    }
+
+    #[test]
+    fn should_pass_note_off_for_passed_note_on() {
+        let mut gate = ProbabilityGate::new(FixedRandom(vec![LOW]), 50, GateMode::PerNote);
+        gate.process(note_on(60));
+        assert_eq!(gate.process(note_off(60)), Some(note_off(60)));
+        assert_eq!(gate.process(note_off(60)), None);
+    }
+
+    #[test]
+    fn should_pass_note_off_for_each_retriggered_note_on() {
+        let mut gate = ProbabilityGate::new(FixedRandom(vec![LOW, LOW]), 50, GateMode::PerNote);
+        gate.process(note_on(60));
+        gate.process(note_on(60));
+        assert_eq!(gate.process(note_off(60)), Some(note_off(60)));
+        assert_eq!(gate.process(note_off(60)), Some(note_off(60)));
+        assert_eq!(gate.process(note_off(60)), None);
+    }
+
+    #[test]
+    fn should_drop_retrigger_when_all_slots_are_used() {
+        let rolls = vec![LOW; 2 * RETRIGGER_SLOTS + 4];
+        let mut gate = ProbabilityGate::new(FixedRandom(rolls), 50, GateMode::PerNote);
+        for note in 0..=RETRIGGER_SLOTS as u8 {
+            gate.process(note_on(note));
+        }
+        for note in 0..RETRIGGER_SLOTS as u8 {
+            assert_eq!(gate.process(note_on(note)), Some(note_on(note)));
+        }
+
+        let last = RETRIGGER_SLOTS as u8;
+        assert_eq!(gate.process(note_on(last)), None);
+        assert_eq!(gate.process(note_off(last)), Some(note_off(last)));
+        assert_eq!(gate.process(note_off(last)), None);
+
+        // Releasing a retriggered note frees its slot
+        gate.process(note_off(0));
+        gate.process(note_on(last));
+        assert_eq!(gate.process(note_on(last)), Some(note_on(last)));
+    }
+
+    #[test]
+    fn should_drop_note_off_for_dropped_note_on() {
+        let mut gate = ProbabilityGate::new(FixedRandom(vec![HIGH]), 50, GateMode::PerNote);
+        gate.process(note_on(60));
+        assert_eq!(gate.process(note_off(60)), None);
+    }
+
+    #[test]
+    fn should_treat_zero_velocity_note_on_as_note_off() {
+        let mut gate = ProbabilityGate::new(FixedRandom(vec![LOW]), 50, GateMode::PerNote);
+        let release = MidiMessage::NoteOn(1.into(), 60.into(), 0.into());
+        assert_eq!(gate.process(release), None);
+        gate.process(note_on(60));
+        assert_eq!(gate.process(release), Some(release));
+    }
+
+    #[test]
+    fn should_track_notes_per_channel() {
+        let mut gate = ProbabilityGate::new(FixedRandom(vec![LOW]), 50, GateMode::PerNote);
+        gate.process(note_on(60));
+        let other_channel = MidiMessage::NoteOff(2.into(), 60.into(), 0x40.into());
+        assert_eq!(gate.process(other_channel), None);
+    }
+
+    #[test]
+    fn should_pass_other_messages() {
+        let mut gate = ProbabilityGate::new(FixedRandom(vec![]), 0, GateMode::PerNote);
+        assert_eq!(
+            gate.process(MidiMessage::TimingClock),
+            Some(MidiMessage::TimingClock)
+        );
+    }
+
+    #[test]
+    fn should_share_roll_within_step() {
+        let mut gate = ProbabilityGate::new(FixedRandom(vec![HIGH, LOW]), 50, GateMode::PerStep);
+        assert_eq!(gate.process(note_on(60)), None);
+        assert_eq!(gate.process(note_on(64)), None);
+        gate.next_step();
+        assert_eq!(gate.process(note_on(60)), Some(note_on(60)));
+        assert_eq!(gate.process(note_on(64)), Some(note_on(64)));
+    }
+
+    #[test]
+    fn should_clamp_probability() {
+        let mut gate = ProbabilityGate::new(FixedRandom(vec![HIGH]), 200, GateMode::PerNote);
+        assert_eq!(gate.probability(), 100);
+        assert_eq!(gate.process(note_on(60)), Some(note_on(60)));
+    }
+
+    #[test]
+    fn seeded_gates_should_be_repeatable() {
+        let mut first = ProbabilityGate::with_seed(1234, 50, GateMode::PerNote);
+        let mut second = ProbabilityGate::with_seed(1234, 50, GateMode::PerNote);
+        for note in 0..100 {
+            assert_eq!(first.process(note_on(note)), second.process(note_on(note)));
+        }
+    }
+}
@@ -0,0 +1,696 @@
+//! Read standard midi files
+//!
+//! Tracks are read from any byte iterator so each track chunk can be streamed from its own file
+//! read instead of loading the whole file into memory. The `TrackMerger` combines the tracks of a
+//! type 1 file into a single stream of messages ordered by time, using the tempo map to convert
+//! ticks to microseconds.
+use crate::parser::MidiParser;
+use midi_types::MidiMessage;
+
+/// Tempo used until the first tempo meta event, 120 beats per minute
+const DEFAULT_TEMPO: u32 = 500_000;
+
+/// Errors that can occur while reading a midi file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SmfError {
+    /// The file does not start with a valid `MThd` header chunk
+    InvalidHeader,
+    /// Chunk length points past the end of the data
+    InvalidChunk,
+    /// The track data ended in the middle of an event
+    UnexpectedEnd,
+    /// A variable length quantity was longer than four bytes
+    InvalidLength,
+    /// A byte that cannot start an event, for example a data byte without running status
+    InvalidEvent(u8),
+}
+
+/// Time division of a midi file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Division {
+    /// Ticks are a fraction of a quarter note, the actual length depends on the tempo
+    TicksPerQuarter(u16),
+    /// Ticks are a fraction of a SMPTE frame and do not depend on tempo. A frame rate of 29
+    /// stands for 29.97 drop-frame.
+    Smpte {
+        frames_per_second: u8,
+        ticks_per_frame: u8,
+    },
+}
+
+impl From<u16> for Division {
+    fn from(value: u16) -> Self {
+        if value & 0x8000 == 0 {
+            Division::TicksPerQuarter(value)
+        } else {
+            Division::Smpte {
+                frames_per_second: ((value >> 8) as u8 as i8).wrapping_neg() as u8,
+                ticks_per_frame: value as u8,
+            }
+        }
+    }
+}
+
+/// Contents of the `MThd` header chunk
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Header {
+    /// File format, 0 for a single track, 1 for simultaneous tracks, 2 for independent tracks
+    pub format: u16,
+    /// Number of track chunks following the header
+    pub tracks: u16,
+    /// Meaning of the delta times in the tracks
+    pub division: Division,
+}
+
+impl Header {
+    /// Parse the header from the start of a midi file
+    pub fn parse(bytes: &[u8]) -> Result<Self, SmfError> {
+        let chunk = ChunkHeader::parse(bytes)?;
+        if &chunk.id != b"MThd" || chunk.length < 6 || bytes.len() < 14 {
+            return Err(SmfError::InvalidHeader);
+        }
+
+        Ok(Header {
+            format: read_u16(&bytes[8..10]),
+            tracks: read_u16(&bytes[10..12]),
+            division: read_u16(&bytes[12..14]).into(),
+        })
+    }
+}
+
+/// Id and length of a chunk, read these eight bytes first to find out how much data to read or
+/// skip when streaming a file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChunkHeader {
+    /// Four character chunk type, `MThd` or `MTrk` for standard chunks
+    pub id: [u8; 4],
+    /// Length of the chunk data following the header in bytes
+    pub length: u32,
+}
+
+impl ChunkHeader {
+    /// Parse the first eight bytes of a chunk
+    pub fn parse(bytes: &[u8]) -> Result<Self, SmfError> {
+        if bytes.len() < 8 {
+            return Err(SmfError::InvalidChunk);
+        }
+
+        Ok(ChunkHeader {
+            id: [bytes[0], bytes[1], bytes[2], bytes[3]],
+            length: read_u32(&bytes[4..8]),
+        })
+    }
+
+    /// Returns true for `MTrk` chunks
+    pub fn is_track(&self) -> bool {
+        &self.id == b"MTrk"
+    }
+}
+
+/// Iterates over the chunks of a midi file that is completely in memory, returning the header
+/// and the data of each chunk.
+#[derive(Debug, Clone)]
+pub struct Chunks<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Chunks<'a> {
+    /// Iterate over the chunks in `bytes`, this includes the header chunk
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Chunks { bytes }
+    }
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = Result<(ChunkHeader, &'a [u8]), SmfError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.bytes.is_empty() {
+            return None;
+        }
+
+        let result = ChunkHeader::parse(self.bytes).and_then(|header| {
+            let end = (header.length as usize)
+                .checked_add(8)
+                .filter(|end| *end <= self.bytes.len())
+                .ok_or(SmfError::InvalidChunk)?;
+            let data = &self.bytes[8..end];
+            self.bytes = &self.bytes[end..];
+            Ok((header, data))
+        });
+
+        if result.is_err() {
+            self.bytes = &[];
+        }
+        Some(result)
+    }
+}
+
+/// Event read from a track
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrackEventKind {
+    /// Midi message to be sent
+    Midi(MidiMessage),
+    /// Tempo change in microseconds per quarter note
+    Tempo(u32),
+}
+
+/// Event read from a track with its time in ticks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrackEvent {
+    /// Ticks since the previous event returned from this track
+    pub delta: u32,
+    /// Ticks since the start of the track
+    pub ticks: u32,
+    /// What happens at this time
+    pub kind: TrackEventKind,
+}
+
+/// Reads events from the data of a single `MTrk` chunk. System exclusive and meta events other
+/// than tempo changes are skipped, their delta times are added to the next event returned.
+#[derive(Debug, Clone)]
+pub struct TrackReader<I> {
+    bytes: I,
+    parser: MidiParser,
+    running_status: Option<u8>,
+    ticks: u32,
+    peeked: Option<Option<Result<TrackEvent, SmfError>>>,
+    finished: bool,
+}
+
+impl<I> TrackReader<I>
+where
+    I: Iterator<Item = u8>,
+{
+    /// Create a reader for track data, this is the chunk data without the eight byte chunk header
+    pub fn new(bytes: I) -> Self {
+        TrackReader {
+            bytes,
+            parser: MidiParser::new(),
+            running_status: None,
+            ticks: 0,
+            peeked: None,
+            finished: false,
+        }
+    }
+
+    /// Return the next event without consuming it
+    pub fn peek(&mut self) -> Option<&Result<TrackEvent, SmfError>> {
+        if self.peeked.is_none() {
+            self.peeked = Some(self.read_event());
+        }
+        self.peeked.as_ref().and_then(|event| event.as_ref())
+    }
+
+    fn read_event(&mut self) -> Option<Result<TrackEvent, SmfError>> {
+        if self.finished {
+            return None;
+        }
+
+        let start = self.ticks;
+        loop {
+            // Running out of data before the end of track event is tolerated
+            let first = self.bytes.next()?;
+            let result = self
+                .read_var_len_from(first)
+                .and_then(|delta| {
+                    self.ticks = self.ticks.wrapping_add(delta);
+                    self.read_event_body()
+                })
+                .transpose();
+
+            match result {
+                Some(Ok(kind)) => {
+                    return Some(Ok(TrackEvent {
+                        delta: self.ticks.wrapping_sub(start),
+                        ticks: self.ticks,
+                        kind,
+                    }))
+                }
+                Some(Err(error)) => {
+                    self.finished = true;
+                    return Some(Err(error));
+                }
+                None if self.finished => return None,
+                None => continue,
+            }
+        }
+    }
+
+    /// Read the event following a delta time, returns none for skipped events
+    fn read_event_body(&mut self) -> Result<Option<TrackEventKind>, SmfError> {
+        let byte = self.read_byte()?;
+        match byte {
+            0xff => {
+                // Meta events cancel running status
+                self.running_status = None;
+                let meta_type = self.read_byte()?;
+                let length = self.read_var_len()?;
+                match (meta_type, length) {
+                    (0x51, 3) => {
+                        let tempo = (u32::from(self.read_byte()?) << 16)
+                            | (u32::from(self.read_byte()?) << 8)
+                            | u32::from(self.read_byte()?);
+                        Ok(Some(TrackEventKind::Tempo(tempo)))
+                    }
+                    (0x2f, _) => {
+                        // End of track
+                        self.finished = true;
+                        Ok(None)
+                    }
+                    _ => {
+                        self.skip(length)?;
+                        Ok(None)
+                    }
+                }
+            }
+            0xf0 | 0xf7 => {
+                // System exclusive events cancel running status
+                self.running_status = None;
+                let length = self.read_var_len()?;
+                self.skip(length)?;
+                Ok(None)
+            }
+            0x80..=0xef => {
+                self.running_status = Some(byte);
+                self.read_channel_message(byte, None).map(Some)
+            }
+            0x00..=0x7f => match self.running_status {
+                Some(status) => self.read_channel_message(status, Some(byte)).map(Some),
+                None => Err(SmfError::InvalidEvent(byte)),
+            },
+            _ => Err(SmfError::InvalidEvent(byte)),
+        }
+    }
+
+    fn read_channel_message(
+        &mut self,
+        status: u8,
+        mut data: Option<u8>,
+    ) -> Result<TrackEventKind, SmfError> {
+        // The parser keeps running state from the previous message, feed the status byte anyway
+        // so it does not matter which message came before.
+        self.parser.parse_byte(status);
+        loop {
+            let byte = match data.take() {
+                Some(byte) => byte,
+                None => self.read_byte()?,
+            };
+            if byte & 0x80 != 0 {
+                return Err(SmfError::InvalidEvent(byte));
+            }
+            if let Some(message) = self.parser.parse_byte(byte) {
+                return Ok(TrackEventKind::Midi(message));
+            }
+        }
+    }
+
+    fn read_byte(&mut self) -> Result<u8, SmfError> {
+        self.bytes.next().ok_or(SmfError::UnexpectedEnd)
+    }
+
+    fn read_var_len(&mut self) -> Result<u32, SmfError> {
+        let first = self.read_byte()?;
+        self.read_var_len_from(first)
+    }
+
+    fn read_var_len_from(&mut self, first: u8) -> Result<u32, SmfError> {
+        let mut value = u32::from(first & 0x7f);
+        let mut byte = first;
+        for _ in 0..3 {
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            byte = self.read_byte()?;
+            value = (value << 7) | u32::from(byte & 0x7f);
+        }
+
+        if byte & 0x80 == 0 {
+            Ok(value)
+        } else {
+            Err(SmfError::InvalidLength)
+        }
+    }
+
+    fn skip(&mut self, length: u32) -> Result<(), SmfError> {
+        for _ in 0..length {
+            self.read_byte()?;
+        }
+        Ok(())
+    }
+}
+
+impl<I> Iterator for TrackReader<I>
+where
+    I: Iterator<Item = u8>,
+{
+    type Item = Result<TrackEvent, SmfError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.peeked.take() {
+            Some(event) => event,
+            None => self.read_event(),
+        }
+    }
+}
+
+/// Midi message from a merged set of tracks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MergedEvent {
+    /// Ticks since the start of the file
+    pub ticks: u32,
+    /// Microseconds since the start of the file
+    pub micros: u64,
+    /// Index of the track the message came from
+    pub track: usize,
+    /// Midi message to be sent at this time
+    pub message: MidiMessage,
+}
+
+/// Merges the tracks of a type 1 midi file into a single stream of messages ordered by time.
+/// Tempo changes from any track are applied to all tracks. Events at the same tick are returned
+/// in track order, so tempo changes in the first track take effect before notes in other tracks.
+#[derive(Debug)]
+pub struct TrackMerger<'a, I> {
+    tracks: &'a mut [TrackReader<I>],
+    division: Division,
+    tempo: u32,
+    tempo_ticks: u32,
+    tempo_micros: u64,
+}
+
+impl<'a, I> TrackMerger<'a, I>
+where
+    I: Iterator<Item = u8>,
+{
+    /// Create a merger over the tracks of a file, `division` is taken from the file header
+    pub fn new(tracks: &'a mut [TrackReader<I>], division: Division) -> Self {
+        TrackMerger {
+            tracks,
+            division,
+            tempo: DEFAULT_TEMPO,
+            tempo_ticks: 0,
+            tempo_micros: 0,
+        }
+    }
+
+    /// Current tempo in microseconds per quarter note
+    pub fn tempo(&self) -> u32 {
+        self.tempo
+    }
+
+    /// Convert an absolute time in ticks to microseconds using the tempo map read so far
+    fn micros(&self, ticks: u32) -> u64 {
+        match self.division {
+            Division::TicksPerQuarter(ticks_per_quarter) => {
+                let elapsed = u64::from(ticks.wrapping_sub(self.tempo_ticks));
+                self.tempo_micros
+                    + elapsed * u64::from(self.tempo) / u64::from(ticks_per_quarter.max(1))
+            }
+            Division::Smpte {
+                frames_per_second: 29,
+                ticks_per_frame,
+            } => u64::from(ticks) * 100_000_000 / (2997 * u64::from(ticks_per_frame.max(1))),
+            Division::Smpte {
+                frames_per_second,
+                ticks_per_frame,
+            } => {
+                u64::from(ticks) * 1_000_000
+                    / (u64::from(frames_per_second.max(1)) * u64::from(ticks_per_frame.max(1)))
+            }
+        }
+    }
+
+    /// Find the track with the earliest next event, errors are returned first
+    fn next_track(&mut self) -> Option<usize> {
+        let mut earliest: Option<(usize, u32)> = None;
+        for (index, track) in self.tracks.iter_mut().enumerate() {
+            match track.peek() {
+                Some(Ok(event)) => match earliest {
+                    Some((_, ticks)) if ticks <= event.ticks => (),
+                    _ => earliest = Some((index, event.ticks)),
+                },
+                Some(Err(_)) => return Some(index),
+                None => (),
+            }
+        }
+        earliest.map(|(index, _)| index)
+    }
+}
+
+impl<'a, I> Iterator for TrackMerger<'a, I>
+where
+    I: Iterator<Item = u8>,
+{
+    type Item = Result<MergedEvent, SmfError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let track = self.next_track()?;
+            let event = match self.tracks[track].next()? {
+                Ok(event) => event,
+                Err(error) => return Some(Err(error)),
+            };
+
+            match event.kind {
+                TrackEventKind::Tempo(tempo) => {
+                    self.tempo_micros = self.micros(event.ticks);
+                    self.tempo_ticks = event.ticks;
+                    self.tempo = tempo;
+                }
+                TrackEventKind::Midi(message) => {
+                    return Some(Ok(MergedEvent {
+                        ticks: event.ticks,
+                        micros: self.micros(event.ticks),
+                        track,
+                        message,
+                    }))
+                }
+            }
+        }
+    }
+}
+
+fn read_u16(bytes: &[u8]) -> u16 {
+    (u16::from(bytes[0]) << 8) | u16::from(bytes[1])
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    (u32::from(bytes[0]) << 24)
+        | (u32::from(bytes[1]) << 16)
+        | (u32::from(bytes[2]) << 8)
+        | u32::from(bytes[3])
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use core::iter::Copied;
+    use core::slice::Iter;
+    use std::vec::Vec;
+
+    #[rustfmt::skip]
+    const TYPE_1_FILE: &[u8] = &[
+        b'M', b'T', b'h', b'd', 0, 0, 0, 6,
+        0, 1, // Format 1
+        0, 2, // Two tracks
+        0, 96, // 96 ticks per quarter note
+        // Tempo track
+        b'M', b'T', b'r', b'k', 0, 0, 0, 18,
+        0x00, 0xff, 0x51, 0x03, 0x07, 0xa1, 0x20, // 120 bpm
+        0x60, 0xff, 0x51, 0x03, 0x0f, 0x42, 0x40, // 60 bpm after one beat
+        0x00, 0xff, 0x2f, 0x00,
+        // Note track
+        b'M', b'T', b'r', b'k', 0, 0, 0, 21,
+        0x00, 0x90, 0x3c, 0x40, // Note on at 0
+        0x60, 0x3c, 0x00, // Note off at one beat, running status
+        0x00, 0xff, 0x01, 0x02, b'h', b'i', // Text event, skipped
+        0x60, 0x90, 0x3e, 0x40, // Note on at two beats
+        0x00, 0xff, 0x2f, 0x00,
+    ];
+
+    fn tracks(bytes: &[u8]) -> Vec<TrackReader<Copied<Iter<'_, u8>>>> {
+        Chunks::new(&bytes[14..])
+            .map(|chunk| chunk.unwrap())
+            .filter(|(header, _)| header.is_track())
+            .map(|(_, data)| TrackReader::new(data.iter().copied()))
+            .collect()
+    }
+
+    fn note_on(note: u8, velocity: u8) -> MidiMessage {
+        MidiMessage::NoteOn(0.into(), note.into(), velocity.into())
+    }
+
+    #[test]
+    fn should_parse_header() {
+        assert_eq!(
+            Header::parse(TYPE_1_FILE),
+            Ok(Header {
+                format: 1,
+                tracks: 2,
+                division: Division::TicksPerQuarter(96),
+            })
+        );
+    }
+
+    #[test]
+    fn should_reject_invalid_header() {
+        assert_eq!(
+            Header::parse(b"MTrk\0\0\0\x06\0\x01\0\x02\0\x60"),
+            Err(SmfError::InvalidHeader)
+        );
+    }
+
+    #[test]
+    fn should_parse_smpte_division() {
+        assert_eq!(
+            Division::from(0xe728),
+            Division::Smpte {
+                frames_per_second: 25,
+                ticks_per_frame: 40,
+            }
+        );
+    }
+
+    #[test]
+    fn should_reject_truncated_chunk() {
+        let mut chunks = Chunks::new(b"MTrk\0\0\0\x10\0\0");
+        assert_eq!(chunks.next(), Some(Err(SmfError::InvalidChunk)));
+        assert_eq!(chunks.next(), None);
+    }
+
+    #[test]
+    fn should_reject_chunk_length_overflow() {
+        let mut chunks = Chunks::new(b"MTrk\xff\xff\xff\xff\0\0");
+        assert_eq!(chunks.next(), Some(Err(SmfError::InvalidChunk)));
+        assert_eq!(chunks.next(), None);
+    }
+
+    #[test]
+    fn should_read_track_events() {
+        let events: Vec<TrackEvent> = tracks(TYPE_1_FILE)
+            .remove(1)
+            .map(|event| event.unwrap())
+            .collect();
+
+        assert_eq!(
+            events,
+            &[
+                TrackEvent {
+                    delta: 0,
+                    ticks: 0,
+                    kind: TrackEventKind::Midi(note_on(0x3c, 0x40)),
+                },
+                TrackEvent {
+                    delta: 96,
+                    ticks: 96,
+                    kind: TrackEventKind::Midi(note_on(0x3c, 0x00)),
+                },
+                TrackEvent {
+                    delta: 96,
+                    ticks: 192,
+                    kind: TrackEventKind::Midi(note_on(0x3e, 0x40)),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn should_read_multi_byte_delta() {
+        let data = [0x81, 0x00, 0xc2, 0x05];
+        let mut track = TrackReader::new(data.iter().copied());
+        assert_eq!(
+            track.next(),
+            Some(Ok(TrackEvent {
+                delta: 128,
+                ticks: 128,
+                kind: TrackEventKind::Midi(MidiMessage::ProgramChange(2.into(), 5.into())),
+            }))
+        );
+    }
+
+    #[test]
+    fn should_reject_data_byte_without_running_status() {
+        let data = [0x00, 0x3c, 0x40];
+        let mut track = TrackReader::new(data.iter().copied());
+        assert_eq!(track.next(), Some(Err(SmfError::InvalidEvent(0x3c))));
+        assert_eq!(track.next(), None);
+    }
+
+    #[test]
+    fn should_report_truncated_event() {
+        let data = [0x00, 0x90, 0x3c];
+        let mut track = TrackReader::new(data.iter().copied());
+        assert_eq!(track.next(), Some(Err(SmfError::UnexpectedEnd)));
+    }
+
+    #[test]
+    fn should_stop_at_end_of_track() {
+        let data = [0x00, 0xff, 0x2f, 0x00, 0x00, 0x90, 0x3c, 0x40];
+        let mut track = TrackReader::new(data.iter().copied());
+        assert_eq!(track.next(), None);
+    }
+
+    #[test]
+    fn should_merge_tracks_with_tempo_map() {
+        let mut tracks = tracks(TYPE_1_FILE);
+        let merger = TrackMerger::new(&mut tracks, Division::TicksPerQuarter(96));
+        let events: Vec<MergedEvent> = merger.map(|event| event.unwrap()).collect();
+
+        assert_eq!(
+            events,
+            &[
+                MergedEvent {
+                    ticks: 0,
+                    micros: 0,
+                    track: 1,
+                    message: note_on(0x3c, 0x40),
+                },
+                MergedEvent {
+                    ticks: 96,
+                    micros: 500_000,
+                    track: 1,
+                    message: note_on(0x3c, 0x00),
+                },
+                MergedEvent {
+                    ticks: 192,
+                    micros: 1_500_000,
+                    track: 1,
+                    message: note_on(0x3e, 0x40),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn should_order_events_from_different_tracks() {
+        let first = [0x00, 0x90, 0x3c, 0x40, 0x20, 0x3c, 0x00];
+        let second = [0x10, 0x91, 0x40, 0x40, 0x20, 0x40, 0x00];
+        let mut tracks = [
+            TrackReader::new(first.iter().copied()),
+            TrackReader::new(second.iter().copied()),
+        ];
+        let ticks: Vec<(u32, usize)> = TrackMerger::new(&mut tracks, Division::TicksPerQuarter(96))
+            .map(|event| event.unwrap())
+            .map(|event| (event.ticks, event.track))
+            .collect();
+
+        assert_eq!(ticks, &[(0, 0), (16, 1), (32, 0), (48, 1)]);
+    }
+
+    #[test]
+    fn should_use_smpte_time() {
+        let data = [0x00, 0x90, 0x3c, 0x40, 0x81, 0x48, 0x3c, 0x00];
+        let mut tracks = [TrackReader::new(data.iter().copied())];
+        let division = Division::Smpte {
+            frames_per_second: 25,
+            ticks_per_frame: 40,
+        };
+        let micros: Vec<u64> = TrackMerger::new(&mut tracks, division)
+            .map(|event| event.unwrap().micros)
+            .collect();
+
+        assert_eq!(micros, &[0, 200_000]);
+    }
+}